@@ -0,0 +1,27 @@
+extern crate gelf;
+
+use gelf::crypto;
+use std::io::net::udp::*;
+use std::io::net::ip::{Ipv4Addr, SocketAddr};
+
+fn main() {
+    encrypted_udp_sender();
+}
+
+fn encrypted_udp_sender() {
+    let listen_addr = SocketAddr { ip: Ipv4Addr(127, 0, 0, 1), port: 9600 };
+    let send_addr = SocketAddr { ip: Ipv4Addr(127, 0, 0, 1), port: 9601 };
+    let json = r#"{"message":"foo","host":"bar","_utf8":"✓"}"#;
+
+    let key: crypto::Key = [7u8; 32];
+    let nonce = [1u8; 12];
+    let datagram = crypto::encrypt(json.as_bytes(), &key, &nonce);
+
+    match UdpSocket::bind(send_addr) {
+        Ok(ref mut client) => {
+            println!("sending encrypted datagram from {} to {}", send_addr, listen_addr);
+            client.send_to(datagram.as_slice(), listen_addr).unwrap()
+        }
+        Err(..) => panic!()
+    }
+}
@@ -0,0 +1,109 @@
+//! Optional ChaCha20-Poly1305 AEAD layer for GELF/UDP.
+//!
+//! Each datagram is laid out as `[12-byte nonce][ciphertext][16-byte tag]`.
+//! A `Receiver` configured with one or more trusted keys tries each key in
+//! turn; the first one whose tag verifies wins, so keys can be rotated by
+//! accepting both the old and new key for the overlap window.
+
+use std::iter::repeat;
+
+use aead_crypto::aead::{AeadDecryptor, AeadEncryptor};
+use aead_crypto::chacha20poly1305::ChaCha20Poly1305;
+
+use error::GelfError;
+
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+pub type Key = [u8; 32];
+
+/// Verify `datagram` against each key in turn and return the decrypted
+/// plaintext from the first key whose tag matches.
+///
+/// Returns `GelfError::DecryptionFailed` if no key verifies, or the
+/// datagram is too short to contain a nonce and a tag.
+pub fn decrypt(datagram: &[u8], keys: &[Key]) -> Result<Vec<u8>, GelfError> {
+    if datagram.len() < NONCE_LEN + TAG_LEN {
+        return Err(GelfError::DecryptionFailed);
+    }
+
+    let nonce = &datagram[..NONCE_LEN];
+    let tag = &datagram[datagram.len() - TAG_LEN..];
+    let ciphertext = &datagram[NONCE_LEN..datagram.len() - TAG_LEN];
+
+    for key in keys.iter() {
+        let mut cipher = ChaCha20Poly1305::new(key, nonce, &[]);
+        let mut plaintext: Vec<u8> = repeat(0u8).take(ciphertext.len()).collect();
+        if cipher.decrypt(ciphertext, plaintext.as_mut_slice(), tag) {
+            return Ok(plaintext);
+        }
+    }
+
+    Err(GelfError::DecryptionFailed)
+}
+
+/// Encrypt `plaintext` under `key`, producing a datagram laid out as
+/// `[12-byte nonce][ciphertext][16-byte tag]`. Used by the
+/// `encrypted_sender` example and tests to round-trip against `decrypt`.
+pub fn encrypt(plaintext: &[u8], key: &Key, nonce: &[u8; NONCE_LEN]) -> Vec<u8> {
+    let mut cipher = ChaCha20Poly1305::new(key, nonce, &[]);
+    let mut ciphertext: Vec<u8> = repeat(0u8).take(plaintext.len()).collect();
+    let mut tag = [0u8; TAG_LEN];
+    cipher.encrypt(plaintext, ciphertext.as_mut_slice(), &mut tag);
+
+    let mut datagram = Vec::with_capacity(NONCE_LEN + ciphertext.len() + TAG_LEN);
+    datagram.push_all(nonce);
+    datagram.push_all(ciphertext.as_slice());
+    datagram.push_all(&tag);
+    datagram
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trips_with_the_right_key() {
+        let key: Key = [7u8; 32];
+        let nonce = [1u8; 12];
+        let plaintext = b"{\"message\":\"foo\",\"host\":\"bar\"}";
+
+        let datagram = encrypt(plaintext, &key, &nonce);
+        let recovered = decrypt(datagram.as_slice(), &[key]).unwrap();
+
+        assert_eq!(plaintext.to_vec(), recovered);
+    }
+
+    #[test]
+    fn tries_every_key_in_the_set() {
+        let wrong_key: Key = [1u8; 32];
+        let right_key: Key = [2u8; 32];
+        let nonce = [3u8; 12];
+        let plaintext = b"{\"message\":\"foo\",\"host\":\"bar\"}";
+
+        let datagram = encrypt(plaintext, &right_key, &nonce);
+        let recovered = decrypt(datagram.as_slice(), &[wrong_key, right_key]).unwrap();
+
+        assert_eq!(plaintext.to_vec(), recovered);
+    }
+
+    #[test]
+    fn fails_closed_when_no_key_verifies() {
+        let key: Key = [7u8; 32];
+        let other_key: Key = [9u8; 32];
+        let nonce = [1u8; 12];
+        let plaintext = b"{\"message\":\"foo\",\"host\":\"bar\"}";
+
+        let datagram = encrypt(plaintext, &key, &nonce);
+
+        assert!(decrypt(datagram.as_slice(), &[other_key]).is_err());
+    }
+
+    #[test]
+    fn fails_closed_on_short_datagrams() {
+        let key: Key = [7u8; 32];
+        let datagram = [0u8; 4];
+
+        assert!(decrypt(&datagram, &[key]).is_err());
+    }
+}
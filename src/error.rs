@@ -0,0 +1,99 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::str;
+
+/// The failure modes of unpacking and reassembling a GELF message.
+///
+/// Every fallible entry point in `message` and `receiver` returns this
+/// instead of `io::Error`, so callers can match on the kind of failure
+/// rather than scrape a description string.
+#[derive(Debug)]
+pub enum GelfError {
+    /// An I/O error unrelated to decompression, e.g. a failed socket op.
+    Io(io::Error),
+    /// The gzip/zlib decoder failed to inflate a packet's payload.
+    Decompress(io::Error),
+    /// A packet was shorter than the minimum GELF requires.
+    ShortPacket { got: usize, need: usize },
+    /// An uncompressed payload was not valid UTF-8.
+    NonUtf8(str::Utf8Error),
+    /// A chunked packet's header was malformed or truncated.
+    BadChunkHeader,
+    /// A chunk arrived with a `sequence_number` already held by its `ChunkSet`.
+    DuplicateSequence(u8),
+    /// A chunk's `sequence_number` didn't fit within its `ChunkSet`'s `sequence_count`.
+    SequenceOutOfRange { got: u8, count: u8 },
+    /// A datagram's AEAD tag didn't verify under any of the receiver's trusted keys.
+    DecryptionFailed,
+    /// A `Message` payload was not well-formed JSON, or was not a JSON object.
+    MalformedJson,
+    /// A `Message` payload was missing a required GELF field.
+    MissingField(&'static str),
+    /// A `Message` payload used a reserved additional-field key (e.g. `_id`).
+    ReservedField(&'static str),
+}
+
+impl fmt::Display for GelfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            GelfError::Io(ref e) => write!(f, "GELF: I/O error: {}", e),
+            GelfError::Decompress(ref e) => write!(f, "GELF: decompression failed: {}", e),
+            GelfError::ShortPacket { got, need } =>
+                write!(f, "GELF: packet too short, got {} bytes, need at least {}", got, need),
+            GelfError::NonUtf8(ref e) => write!(f, "GELF: non-UTF8 payload: {}", e),
+            GelfError::BadChunkHeader =>
+                write!(f, "GELF: chunked message header is malformed or truncated"),
+            GelfError::DuplicateSequence(n) =>
+                write!(f, "GELF: duplicate chunk sequence number {}", n),
+            GelfError::SequenceOutOfRange { got, count } =>
+                write!(f, "GELF: chunk sequence number {} out of range for count {}", got, count),
+            GelfError::DecryptionFailed =>
+                write!(f, "GELF: datagram did not verify under any trusted key"),
+            GelfError::MalformedJson =>
+                write!(f, "GELF: message payload is not well-formed JSON"),
+            GelfError::MissingField(name) =>
+                write!(f, "GELF: message is missing required field '{}'", name),
+            GelfError::ReservedField(name) =>
+                write!(f, "GELF: message uses reserved additional field '{}'", name),
+        }
+    }
+}
+
+impl Error for GelfError {
+    fn description(&self) -> &str {
+        match *self {
+            GelfError::Io(..) => "I/O error",
+            GelfError::Decompress(..) => "decompression failed",
+            GelfError::ShortPacket { .. } => "packet too short",
+            GelfError::NonUtf8(..) => "non-UTF8 payload",
+            GelfError::BadChunkHeader => "malformed chunk header",
+            GelfError::DuplicateSequence(..) => "duplicate chunk sequence number",
+            GelfError::SequenceOutOfRange { .. } => "chunk sequence number out of range",
+            GelfError::DecryptionFailed => "AEAD tag verification failed for every trusted key",
+            GelfError::MalformedJson => "message payload is not well-formed JSON",
+            GelfError::MissingField(..) => "message is missing a required field",
+            GelfError::ReservedField(..) => "message uses a reserved additional field",
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            GelfError::Io(ref e) | GelfError::Decompress(ref e) => Some(e),
+            GelfError::NonUtf8(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for GelfError {
+    fn from(e: io::Error) -> GelfError {
+        GelfError::Io(e)
+    }
+}
+
+impl From<str::Utf8Error> for GelfError {
+    fn from(e: str::Utf8Error) -> GelfError {
+        GelfError::NonUtf8(e)
+    }
+}
@@ -3,8 +3,17 @@
 
 extern crate flate2;
 extern crate time;
+extern crate crypto as aead_crypto;
+extern crate serde;
+extern crate serde_json;
+#[macro_use]
+extern crate crossbeam_channel;
 #[cfg(test)]
 extern crate rand;
 
+pub mod crypto;
+pub mod error;
 pub mod message;
 pub mod receiver;
+
+pub use error::GelfError;
@@ -1,7 +1,7 @@
-use std::old_io;
-use std::old_io::{IoError, IoResult};
 use time::{get_time, Timespec};
 
+use error::GelfError;
+
 #[derive(Clone, Show)]
 pub struct Chunk {
     pub id: Vec<u8>,
@@ -12,7 +12,7 @@ pub struct Chunk {
 }
 
 impl Chunk {
-    pub fn from_packet(packet: &[u8]) -> IoResult<Chunk> {
+    pub fn from_packet(packet: &[u8]) -> Result<Chunk, GelfError> {
         if packet.len() > 12 {
             Ok(Chunk {
                 id: packet[2..10].to_vec(),
@@ -22,11 +22,7 @@ impl Chunk {
                 arrival: get_time()
             })
         } else {
-            Err(IoError {
-                kind: old_io::InvalidInput,
-                desc: "Unsupported GELF: Chunked message must be at least 12 bytes long.",
-                detail: None,
-            })
+            Err(GelfError::BadChunkHeader)
         }
     }
 }
@@ -1,33 +1,34 @@
 use std::io::prelude::*;
-use std::io;
 use flate2::read::{GzDecoder, ZlibDecoder};
 use std::str;
 
+use error::GelfError;
+
 pub use self::chunk::Chunk;
+pub use self::model::Message;
 pub use self::Payload::*;
 
 pub mod chunk;
+pub mod model;
 
 pub enum Payload {
     Complete(String),
     Partial(Chunk)
 }
 
-pub fn unpack(packet: &[u8]) -> io::Result<Payload> {
+pub fn unpack(packet: &[u8]) -> Result<Payload, GelfError> {
     match packet {
         [0x1e, 0x0f, ..] => Ok(Partial(try!(Chunk::from_packet(packet)))),
         _                => Ok(Complete(try!(unpack_complete(packet))))
     }
 }
 
-pub fn unpack_complete(packet: &[u8]) -> io::Result<String> {
+pub fn unpack_complete(packet: &[u8]) -> Result<String, GelfError> {
     match packet {
         [0x1f, 0x8b, ..]            => unpack_gzip(packet),
         [0x78, y, ..] if is_zlib(y) => unpack_zlib(packet),
         [_, _, ..]                  => unpack_uncompressed(packet),
-        _                           => Err(io::Error::new(
-                                             io::ErrorKind::InvalidInput,
-                                             "GELF: Packet too short, less than 2 bytes."))
+        _                           => Err(GelfError::ShortPacket { got: packet.len(), need: 2 })
     }
 }
 
@@ -36,25 +37,22 @@ fn is_zlib(second_byte: u8) -> bool {
     (256 * 0x78 + second_byte as u16) % 31 == 0
 }
 
-fn unpack_gzip(packet: &[u8]) -> io::Result<String> {
+fn unpack_gzip(packet: &[u8]) -> Result<String, GelfError> {
     let mut string = String::new();
-    let mut decoder = try!(GzDecoder::new(packet));
-    try!(decoder.read_to_string(&mut string));
+    let mut decoder = try!(GzDecoder::new(packet).map_err(GelfError::Decompress));
+    try!(decoder.read_to_string(&mut string).map_err(GelfError::Decompress));
     Ok(string)
 }
 
-fn unpack_zlib(packet: &[u8]) -> io::Result<String> {
+fn unpack_zlib(packet: &[u8]) -> Result<String, GelfError> {
     let mut string = String::new();
-    try!(ZlibDecoder::new(packet).read_to_string(&mut string));
+    try!(ZlibDecoder::new(packet).read_to_string(&mut string).map_err(GelfError::Decompress));
     Ok(string)
 }
 
-fn unpack_uncompressed(packet: &[u8]) -> io::Result<String> {
-    match str::from_utf8(packet) {
-        Ok(payload) => Ok(payload.to_string()),
-        Err(e)      => Err(io::Error::new(io::ErrorKind::InvalidInput,
-                                          "GELF: Unknown, non-UTF8 payload."))
-    }
+fn unpack_uncompressed(packet: &[u8]) -> Result<String, GelfError> {
+    let payload = try!(str::from_utf8(packet));
+    Ok(payload.to_string())
 }
 
 #[cfg(test)]
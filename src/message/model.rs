@@ -0,0 +1,115 @@
+use std::collections::{BTreeMap, HashMap};
+use std::str;
+
+use serde_json;
+use serde_json::Value;
+
+use error::GelfError;
+
+/// A validated GELF message, parsed from the JSON payload `unpack` produces.
+///
+/// Spec fields are typed; every `_`-prefixed additional field is kept in
+/// `additional` as a raw `serde_json::Value` so callers don't lose data this
+/// crate doesn't know about. Any other unknown key is dropped.
+#[derive(Debug, Clone)]
+pub struct Message {
+    pub version: String,
+    pub host: String,
+    pub short_message: String,
+    pub full_message: Option<String>,
+    pub timestamp: Option<f64>,
+    pub level: Option<i64>,
+    pub additional: HashMap<String, Value>
+}
+
+impl Message {
+    pub fn from_slice(bytes: &[u8]) -> Result<Message, GelfError> {
+        let text = try!(str::from_utf8(bytes));
+        Message::from_str(text)
+    }
+
+    pub fn from_str(text: &str) -> Result<Message, GelfError> {
+        let value: Value = try!(serde_json::from_str(text).map_err(|_| GelfError::MalformedJson));
+        let object = try!(value.as_object().ok_or(GelfError::MalformedJson));
+
+        let version = try!(required_string(object, "version"));
+        let host = try!(required_string(object, "host"));
+        let short_message = try!(required_string(object, "short_message"));
+        let full_message = object.get("full_message").and_then(|v| v.as_str()).map(|s| s.to_string());
+        let timestamp = object.get("timestamp").and_then(|v| v.as_f64());
+        let level = object.get("level").and_then(|v| v.as_i64());
+
+        if object.contains_key("_id") {
+            return Err(GelfError::ReservedField("_id"));
+        }
+
+        let mut additional = HashMap::new();
+        for (key, value) in object.iter() {
+            if key.starts_with('_') {
+                additional.insert(key.clone(), value.clone());
+            }
+        }
+
+        Ok(Message {
+            version: version,
+            host: host,
+            short_message: short_message,
+            full_message: full_message,
+            timestamp: timestamp,
+            level: level,
+            additional: additional
+        })
+    }
+}
+
+fn required_string(object: &BTreeMap<String, Value>, field: &'static str) -> Result<String, GelfError> {
+    object.get(field)
+        .and_then(|v| v.as_str())
+        .map(|s| s.to_string())
+        .ok_or(GelfError::MissingField(field))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_required_fields() {
+        let json = r#"{"version":"1.1","host":"bar","short_message":"foo"}"#;
+        let message = Message::from_str(json).unwrap();
+
+        assert_eq!("1.1", message.version);
+        assert_eq!("bar", message.host);
+        assert_eq!("foo", message.short_message);
+        assert_eq!(None, message.full_message);
+    }
+
+    #[test]
+    fn collects_additional_fields() {
+        let json = r#"{"version":"1.1","host":"bar","short_message":"foo","_utf8":"✓"}"#;
+        let message = Message::from_str(json).unwrap();
+
+        assert!(message.additional.contains_key("_utf8"));
+    }
+
+    #[test]
+    fn rejects_missing_required_field() {
+        let json = r#"{"host":"bar","short_message":"foo"}"#;
+
+        assert!(Message::from_str(json).is_err());
+    }
+
+    #[test]
+    fn rejects_reserved_id_field() {
+        let json = r#"{"version":"1.1","host":"bar","short_message":"foo","_id":"nope"}"#;
+
+        assert!(Message::from_str(json).is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_json() {
+        let json = "not json";
+
+        assert!(Message::from_str(json).is_err());
+    }
+}
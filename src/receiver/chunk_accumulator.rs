@@ -1,61 +1,141 @@
-use std::collections::HashMap;
-use std::io::prelude::*;
-use std::io;
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+use std::hash::{Hash, Hasher, SipHasher};
 use std::iter::repeat;
-use std::old_io::timer::Timer;
 use std::ops::Drop;
-use std::sync::{Arc,Mutex};
-use std::sync::mpsc::{channel, Receiver, Sender};
+use std::os;
+use std::sync::{Arc, Mutex};
 use std::thread;
 use std::thread::{JoinGuard, Thread};
-use std::time::Duration;
+use std::time::Duration as StdDuration;
 
+use crossbeam_channel;
+use crossbeam_channel::Sender;
 use time;
 use time::{get_time, Timespec};
 
+use error::GelfError;
 use message::Chunk;
 use message::unpack_complete;
 
 enum Signal {
-    EvictionEntry((Vec<u8>, Duration)),
+    EvictionEntry((Vec<u8>, Timespec)),
     Quit
 }
 
+/// A (deadline, id) pair ordered so that the *soonest* deadline sorts
+/// greatest, making a `BinaryHeap<Deadline>` behave like a min-heap over
+/// `at` (`BinaryHeap::pop` always returns the earliest expiry first).
+struct Deadline {
+    at: Timespec,
+    id: Vec<u8>
+}
+
+impl PartialEq for Deadline {
+    fn eq(&self, other: &Deadline) -> bool {
+        self.at == other.at
+    }
+}
+
+impl Eq for Deadline {}
+
+impl PartialOrd for Deadline {
+    fn partial_cmp(&self, other: &Deadline) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Deadline {
+    fn cmp(&self, other: &Deadline) -> Ordering {
+        other.at.cmp(&self.at)
+    }
+}
+
+/// Reassembles chunked GELF messages, sharded across `N` independent maps
+/// so that UDP traffic for unrelated messages never contends on the same
+/// lock. A chunk's 8-byte `id` is SipHashed (seeded once, at construction,
+/// to avoid a fixed hash-flooding target) and `% N` picks its shard; within
+/// a shard, `accept`/`ChunkSet`/eviction semantics are unchanged from the
+/// single-map accumulator this replaces.
+///
+/// `accept` takes `&self`: every shard synchronizes through its own
+/// `Mutex`, so a `ChunkAccumulator` needs no outer lock to be shared. The
+/// only current caller (`ChannelReceiver`) still drives it from a single
+/// thread, but `&self` is what lets future multi-producer callers (e.g.
+/// several socket reader threads) hand out one `Arc<ChunkAccumulator>` and
+/// call `accept` from all of them concurrently, which is the whole point
+/// of sharding the map in the first place. The whole check-or-insert in
+/// `Shard::accept` runs under one lock guard, so two chunks racing to
+/// start the same id can't each build a one-chunk `ChunkSet` and clobber
+/// one another on insert.
 pub struct ChunkAccumulator {
+    shards: Vec<Shard>,
+    hash_keys: (u64, u64)
+}
+
+impl ChunkAccumulator {
+    /// Shards across one map per CPU.
+    pub fn new() -> ChunkAccumulator {
+        ChunkAccumulator::with_shards(os::num_cpus())
+    }
+
+    pub fn with_shards(count: usize) -> ChunkAccumulator {
+        let seed_at = get_time();
+        ChunkAccumulator {
+            shards: repeat(()).take(count).map(|_| Shard::new()).collect(),
+            hash_keys: (seed_at.sec as u64, seed_at.nsec as u64)
+        }
+    }
+
+    pub fn accept(&self, chunk: Chunk) -> Result<Option<ChunkSet>, GelfError> {
+        let index = self.shard_index(&chunk.id);
+        self.shards[index].accept(chunk)
+    }
+
+    fn shard_index(&self, id: &[u8]) -> usize {
+        let (key0, key1) = self.hash_keys;
+        let mut hasher = SipHasher::new_with_keys(key0, key1);
+        id.hash(&mut hasher);
+        (hasher.finish() % self.shards.len() as u64) as usize
+    }
+}
+
+struct Shard {
     map: Arc<Mutex<HashMap<Vec<u8>, ChunkSet>>>,
     reaper_tx: Sender<Signal>,
     reaper: Option<JoinGuard<'static ()>>
 }
 
-impl ChunkAccumulator {
-    pub fn new() -> ChunkAccumulator {
-        let (tx, rx) = channel();
-        let map_mutex = Arc::new(Mutex::new(HashMap::new()));
-        let reaper_map_mutex = map_mutex.clone();
+impl Shard {
+    fn new() -> Shard {
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let map_lock = Arc::new(Mutex::new(HashMap::new()));
+        let reaper_map_lock = map_lock.clone();
 
         // Start a reaper thread to evict expired chunks from the HashMap. This
-        // thread should have the same lifetime as the struct.
+        // thread should have the same lifetime as the shard.
         let thread = thread::scoped(move|| {
-            ChunkAccumulator::reaper(reaper_map_mutex, rx);
+            Shard::reap(reaper_map_lock, rx);
         });
 
-        ChunkAccumulator {
-            map: map_mutex,
+        Shard {
+            map: map_lock,
             reaper_tx: tx,
             reaper: Some(thread)
         }
     }
 
-    pub fn accept(&mut self, chunk: Chunk) -> io::Result<Option<ChunkSet>> {
+    fn accept(&self, chunk: Chunk) -> Result<Option<ChunkSet>, GelfError> {
         let id = chunk.id.clone();
-        let mut map = self.map.lock().unwrap();
 
-        if (*map).contains_key(&id) {
-            // This is a bit convoluted because of lexical borrows. The
-            // get_mut().unwrap() should never panic because we've already run
-            // contains_key() under a lock(). With non-lexical borrows, this
-            // can be a single match or if-let.
-            let result = (*map).get_mut(&id).unwrap().accept(chunk);
+        // Check-or-insert happens under a single lock guard for the whole
+        // operation. Releasing the lock between the check and the insert
+        // (e.g. a read-then-write pair) would let two chunks racing to
+        // start the same id both miss the check, each build a one-chunk
+        // `ChunkSet`, and the second `insert` silently clobber the first.
+        let mut map = self.map.lock().unwrap();
+        if let Some(set) = (*map).get_mut(&id) {
+            let result = try!(set.accept(chunk));
             return match result {
                 ChunkSetState::Complete => Ok((*map).remove(&id)),
                 _                       => Ok(None),
@@ -63,13 +143,10 @@ impl ChunkAccumulator {
         }
 
         let mut new_set = ChunkSet::new(&chunk);
-        match new_set.accept(chunk) {
+        match try!(new_set.accept(chunk)) {
             ChunkSetState::Complete  => Ok(Some(new_set)),
             ChunkSetState::Partial   => {
-                let eviction_entry = Signal::EvictionEntry((
-                    id.clone(),
-                    new_set.expires_in()
-                ));
+                let eviction_entry = Signal::EvictionEntry((id.clone(), new_set.first_arrival));
                 self.reaper_tx.send(eviction_entry).ok().expect("Communication with Reaper thread failed.");
                 (*map).insert(id, new_set);
                 Ok(None)
@@ -77,45 +154,64 @@ impl ChunkAccumulator {
         }
     }
 
-    fn reaper(map_mutex: Arc<Mutex<HashMap<Vec<u8>, ChunkSet>>>, rx: Receiver<Signal>) {
-        let mut eviction_fifo: Vec<(Vec<u8>, Duration)> = vec![];
-        let mut timer = Timer::new().unwrap();
-        let validity = Duration::seconds(5);
-        // Get a receiver that will never recv() for when we don't have a
-        // timeout.
-        let (_never_tx, never_rx) = channel::<()>();
-        // Move the never_rx into an Option so it isn't aliased as timeout
-        // when used.
-        // @todo this seems ugly, find a better way. Two different select!s?
-        let mut never_rx_opt = Some(never_rx);
+    // The reaper's eviction schedule is a min-heap keyed on expiry, not a
+    // FIFO/LIFO list: each `accept` that creates a new incomplete set sends
+    // `(id, first_arrival)`, and the reaper pushes `first_arrival + validity`
+    // onto the heap and always sleeps until the heap root's deadline. On
+    // wake it pops the root and does *lazy deletion* - the map is only
+    // touched if the id is still present *and* its own deadline has
+    // actually passed, so a set that already completed (and removed itself
+    // from the map) needs no cancellation and the stale heap entry is
+    // simply discarded. The second check also covers id reuse: if a set
+    // completes and a new set for the same id arrives before the original
+    // deadline fires, the resident set's deadline is later than the heap
+    // entry's, so the pop is a no-op instead of evicting a fresh set early.
+    fn reap(map_lock: Arc<Mutex<HashMap<Vec<u8>, ChunkSet>>>, rx: crossbeam_channel::Receiver<Signal>) {
+        let mut schedule: BinaryHeap<Deadline> = BinaryHeap::new();
+        let validity = time::Duration::seconds(5);
 
         loop {
-            let timeout = if eviction_fifo.len() > 0 {
-                let (_, expires_in) = eviction_fifo[0];
-                timer.oneshot(expires_in)
-            } else {
-                never_rx_opt.take().expect("Reaper null receiver was None. This should never happen")
+            let timeout = match schedule.peek() {
+                Some(deadline) => {
+                    let remaining = deadline.at - get_time();
+                    if remaining > time::Duration::zero() {
+                        // Round up, so the wake-up never comes before the deadline.
+                        StdDuration::from_millis(remaining.num_milliseconds() as u64 + 1)
+                    } else {
+                        StdDuration::from_millis(0)
+                    }
+                }
+                None => StdDuration::from_secs(60 * 60 * 24) // Nothing scheduled; wake up occasionally anyway.
             };
-            select!(
-                msg = rx.recv() => match msg.unwrap() {
-                    Signal::EvictionEntry(e) => eviction_fifo.push(e),
-                    Signal::Quit             => break,
+
+            let after = crossbeam_channel::after(timeout);
+            select! {
+                recv(rx) -> signal => {
+                    match signal.unwrap() {
+                        Signal::EvictionEntry((id, arrival)) => {
+                            schedule.push(Deadline { at: arrival + validity, id: id });
+                        },
+                        Signal::Quit => return
+                    }
                 },
-                _ = timeout.recv() => {
-                    let (id, _) = eviction_fifo.remove(0);
-                    let mut map = map_mutex.lock().unwrap();
-                    (*map).remove(&id);
+                recv(after) -> _ => {
+                    if let Some(deadline) = schedule.pop() {
+                        let mut map = map_lock.lock().unwrap();
+                        let expired = match (*map).get(&deadline.id) {
+                            Some(set) => set.first_arrival + validity <= get_time(),
+                            None      => false
+                        };
+                        if expired {
+                            (*map).remove(&deadline.id);
+                        }
+                    }
                 }
-            );
-            // Move never_rx back if we used it.
-            if never_rx_opt.is_none() {
-                never_rx_opt = Some(timeout);
             }
         }
     }
 }
 
-impl Drop for ChunkAccumulator {
+impl Drop for Shard {
     fn drop(&mut self) {
         let _ = self.reaper_tx.send(Signal::Quit);
         if let Some(thread) = self.reaper.take() {
@@ -149,36 +245,35 @@ impl ChunkSet {
         }
     }
 
-    fn accept(&mut self, chunk: Chunk) -> ChunkSetState {
+    fn accept(&mut self, chunk: Chunk) -> Result<ChunkSetState, GelfError> {
+        if chunk.sequence_number == 0 || chunk.sequence_number as usize > self.chunks.len() {
+            return Err(GelfError::SequenceOutOfRange {
+                got: chunk.sequence_number,
+                count: self.chunks.len() as u8,
+            });
+        }
         let number = chunk.sequence_number as usize - 1;
         match self.chunks[number] {
             None => {
                 self.chunks[number] = Some(chunk);
                 self.rcv_count += 1;
                 if self.rcv_count == self.chunks.len() {
-                    ChunkSetState::Complete
+                    Ok(ChunkSetState::Complete)
                 } else {
-                    ChunkSetState::Partial
+                    Ok(ChunkSetState::Partial)
                 }
             },
-            // @todo duplicate packet, error or meh? Java overwrites (maybe)?
-            Some(_) => ChunkSetState::Partial
+            Some(_) => Err(GelfError::DuplicateSequence(chunk.sequence_number))
         }
     }
 
-    fn expires_in(&self) -> Duration {
-        let validity = time::Duration::seconds(5);
-        let eviction_time = self.first_arrival + validity;
-        Duration::seconds((eviction_time - get_time()).num_seconds())
-    }
-
     // TODO Restrict this to complete messages.
-    pub fn unpack(&mut self) -> io::Result<String> {
+    pub fn unpack(&mut self) -> Result<String, GelfError> {
         let mut complete_message = vec![];
         for chunk in self.chunks.drain() {
             complete_message.push_all(chunk.unwrap().payload.as_slice());
         }
-        Ok(try!(unpack_complete(complete_message.as_slice())))
+        unpack_complete(complete_message.as_slice())
     }
 }
 
@@ -201,7 +296,7 @@ mod test {
         let packet = chunks[0].as_slice();
 
         let chunk = Chunk::from_packet(packet).unwrap();
-        let mut acc = ChunkAccumulator::new();
+        let acc = ChunkAccumulator::new();
         let mut chunk_set = acc.accept(chunk).unwrap().unwrap();
         let result = chunk_set.unpack().unwrap();
         assert_eq!(json, result.as_slice());
@@ -217,7 +312,7 @@ mod test {
 
         let chunk1 = Chunk::from_packet(packet1).unwrap();
         let chunk2 = Chunk::from_packet(packet2).unwrap();
-        let mut acc = ChunkAccumulator::new();
+        let acc = ChunkAccumulator::new();
         acc.accept(chunk1).unwrap();
         let mut chunk_set = acc.accept(chunk2).unwrap().unwrap();
         let result = chunk_set.unpack().unwrap();
@@ -225,7 +320,7 @@ mod test {
     }
 
     #[test]
-    fn reaper() {
+    fn reaper_evicts_expired_incomplete_sets() {
         let json = r#"{message":"foo","host":"bar","_utf8":"✓"}"#;
 
         let chunks = chunker(json, 22);
@@ -236,7 +331,8 @@ mod test {
         // Backdate chunk1 arrival so it's already expired.
         chunk1.arrival = time::get_time() - time::Duration::seconds(6);
         let chunk2 = Chunk::from_packet(packet2).unwrap();
-        let mut acc = ChunkAccumulator::new();
+        // Use a single shard so both chunks are guaranteed to land together.
+        let acc = ChunkAccumulator::with_shards(1);
         acc.accept(chunk1).unwrap();
         // Allow reaper thread to run - not bulletproof, but seems to work...
         sleep(Duration::milliseconds(10));
@@ -245,6 +341,83 @@ mod test {
         assert!(option.is_none(), "The first packet expired, so the second shouldn't complete anything");
     }
 
+    #[test]
+    fn reaper_does_not_evict_a_set_that_already_completed() {
+        // A set that completes before its deadline fires removes itself
+        // from the map; the reaper's stale heap entry must be a no-op, not
+        // a removal of whatever now occupies that id (lazy deletion).
+        let json = r#"{message":"foo","host":"bar","_utf8":"✓"}"#;
+
+        let chunks = chunker(json, 22);
+        let packet1 = chunks[0].as_slice();
+        let packet2 = chunks[1].as_slice();
+
+        let mut chunk1 = Chunk::from_packet(packet1).unwrap();
+        // Backdate chunk1 so its deadline falls 50ms from now: after both
+        // chunks are accepted, but inside the sleep below.
+        chunk1.arrival = time::get_time() - time::Duration::seconds(5) + time::Duration::milliseconds(50);
+        let chunk2 = Chunk::from_packet(packet2).unwrap();
+        // Use a single shard so both chunks are guaranteed to land together.
+        let acc = ChunkAccumulator::with_shards(1);
+        acc.accept(chunk1).unwrap();
+        let mut chunk_set = acc.accept(chunk2).unwrap().unwrap();
+        let result = chunk_set.unpack().unwrap();
+        assert_eq!(json, result.as_slice());
+
+        // Let the reaper pop the completed set's stale heap entry.
+        sleep(Duration::milliseconds(100));
+
+        // The pop was a no-op: the same id still reassembles afterwards.
+        let chunk1 = Chunk::from_packet(packet1).unwrap();
+        let chunk2 = Chunk::from_packet(packet2).unwrap();
+        acc.accept(chunk1).unwrap();
+        let mut chunk_set = acc.accept(chunk2).unwrap().unwrap();
+        let result = chunk_set.unpack().unwrap();
+        assert_eq!(json, result.as_slice());
+    }
+
+    #[test]
+    fn reaper_does_not_evict_a_fresh_set_that_reuses_a_completed_ids_schedule() {
+        // `id` is deliberately reused by two unrelated messages. The first
+        // message's eviction entry comes due 50ms from now, after the
+        // second message's first chunk has arrived; the reaper must check
+        // the resident set's own deadline rather than trust the stale entry.
+        let id = b"reused_id".to_vec();
+        let a_arrival = time::get_time() - time::Duration::seconds(5) + time::Duration::milliseconds(50);
+
+        let a1 = Chunk {
+            id: id.clone(), sequence_number: 1, sequence_count: 2,
+            payload: b"aa".to_vec(), arrival: a_arrival
+        };
+        let a2 = Chunk {
+            id: id.clone(), sequence_number: 2, sequence_count: 2,
+            payload: b"bb".to_vec(), arrival: a_arrival
+        };
+
+        // Use a single shard so every chunk is guaranteed to land together.
+        let acc = ChunkAccumulator::with_shards(1);
+        acc.accept(a1).unwrap();
+        acc.accept(a2).unwrap().unwrap(); // Completes and self-removes from the map.
+
+        // A fresh message reuses the same id, well within validity.
+        let b1 = Chunk {
+            id: id.clone(), sequence_number: 1, sequence_count: 2,
+            payload: b"{\"a\":1}".to_vec(), arrival: time::get_time()
+        };
+        acc.accept(b1).unwrap();
+
+        // Give the reaper a chance to pop the first message's overdue
+        // schedule entry against the second message's still-fresh set.
+        sleep(Duration::milliseconds(100));
+
+        let b2 = Chunk {
+            id: id.clone(), sequence_number: 2, sequence_count: 2,
+            payload: b"".to_vec(), arrival: time::get_time()
+        };
+        let option = acc.accept(b2).unwrap();
+        assert!(option.is_some(), "The second message's set should have survived the first message's stale eviction schedule");
+    }
+
     #[test]
     fn two_chunked_messages() {
         let json_a = r#"{message":"foo","host":"bar","_utf8":"✓"}"#;
@@ -259,7 +432,7 @@ mod test {
 
         let chunk_a_1 = Chunk::from_packet(chunks_a[0].as_slice()).unwrap();
         let chunk_a_2 = Chunk::from_packet(chunks_a[1].as_slice()).unwrap();
-        let mut acc = ChunkAccumulator::new();
+        let acc = ChunkAccumulator::new();
         acc.accept(chunk_a_1).unwrap();
         acc.accept(chunk_b_1).unwrap();
         let mut chunk_set_a = acc.accept(chunk_a_2).unwrap().unwrap();
@@ -270,6 +443,20 @@ mod test {
         assert_eq!(json_b, result_b.as_slice());
     }
 
+    #[test]
+    fn shards_route_by_id_consistently() {
+        let json = r#"{message":"foo","host":"bar","_utf8":"✓"}"#;
+        let chunks = chunker(json, 22);
+
+        let chunk1 = Chunk::from_packet(chunks[0].as_slice()).unwrap();
+        let chunk2 = Chunk::from_packet(chunks[1].as_slice()).unwrap();
+        let acc = ChunkAccumulator::with_shards(8);
+        acc.accept(chunk1).unwrap();
+        let mut chunk_set = acc.accept(chunk2).unwrap().unwrap();
+        let result = chunk_set.unpack().unwrap();
+        assert_eq!(json, result.as_slice());
+    }
+
     fn chunker(message: &str, max_length: usize) -> Vec<Vec<u8>> {
         // Test only id.
         let mut id = [0u8; 8];
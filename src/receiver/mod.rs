@@ -1,41 +1,75 @@
 use message::*;
 use std::io::net::udp::*;
+use std::io::net::tcp::{TcpListener, TcpStream};
+use std::io::{Acceptor, Listener};
 use std::io::test::*;
+use std::str;
 use std::sync::{Arc, TaskPool};
-use std::sync::mpsc::channel;
+use std::sync::mpsc::{channel, Sender};
+use std::sync::mpsc::Receiver as MpscReceiver;
 use std::thread::Thread;
 use std::io::net::ip::{Ipv4Addr, SocketAddr};
 
+use crypto;
+use error::GelfError;
+use self::chunk_accumulator::ChunkAccumulator;
+
+mod chunk_accumulator;
+
 pub struct Receiver<H> {
-    handler: H
+    handler: H,
+    keys: Vec<crypto::Key>
 }
 
 impl<H: Handler> Receiver<H> {
     pub fn new(handler: H) -> Receiver<H> {
-        Receiver { handler: handler }
+        Receiver { handler: handler, keys: vec![] }
     }
-    
+
+    /// Decrypt every datagram with `ChaCha20Poly1305` before unpacking it,
+    /// trying each of `keys` in turn. Datagrams that don't verify under any
+    /// key are dropped.
+    pub fn with_keys(handler: H, keys: Vec<crypto::Key>) -> Receiver<H> {
+        Receiver { handler: handler, keys: keys }
+    }
+
     pub fn listen(self, listen_addr: SocketAddr) {
         // let listen_addr = SocketAddr { ip: Ipv4Addr(127, 0, 0, 1), port: 9600 };
         // println!("{}", listen_addr);
-        
+
         match UdpSocket::bind(listen_addr) {
             Ok(ref mut server) => {
                 let pool = TaskPool::new(100);
                 let handler = Arc::new(self.handler);
+                let keys = Arc::new(self.keys);
                 loop {
                     // From gelfclient... CHUNK_MAGIC_BYTES(2) + messageId(8) + sequenceNumber(1) + sequenceCount(1) + MAX_CHUNK_SIZE(1420)
                     let mut buf = [0; 1432];
                     match server.recv_from(&mut buf) {
-                        
+
                         Ok((n_read, _)) => {
                             let handler = handler.clone();
+                            let keys = keys.clone();
                             pool.execute(move || {
-                                
-                                let packet = buf.as_slice().slice_to(n_read);
+
+                                let datagram = buf.as_slice().slice_to(n_read);
+                                let decrypted;
+                                let packet = if keys.is_empty() {
+                                    datagram
+                                } else {
+                                    match crypto::decrypt(datagram, keys.as_slice()) {
+                                        Ok(plaintext) => { decrypted = plaintext; decrypted.as_slice() }
+                                        // Tag didn't verify under any key. `Handler::call` has no
+                                        // error channel to surface a `GelfError` through, so the
+                                        // packet is dropped silently here; use
+                                        // `ChannelReceiver::listen_with_keys` instead when
+                                        // `GelfError::DecryptionFailed` needs to be observable.
+                                        Err(..) => return
+                                    }
+                                };
                                 match unpack(packet).unwrap() {
                                     Partial(_) => assert!(false, "Expected 'Complete' result."),
-                                    Complete(s) => handler.call(s)
+                                    Complete(s) => handler.call(Delivery::new(s))
                                 }
                             });
                         }
@@ -48,6 +82,99 @@ impl<H: Handler> Receiver<H> {
     }
 }
 
+/// Receives GELF/TCP: a long-lived stream of uncompressed, un-chunked JSON
+/// messages, each terminated by a single NUL byte (`0x00`).
+pub struct TcpReceiver<H> {
+    handler: H
+}
+
+impl<H: Handler> TcpReceiver<H> {
+    pub fn new(handler: H) -> TcpReceiver<H> {
+        TcpReceiver { handler: handler }
+    }
+
+    pub fn listen(self, listen_addr: SocketAddr) {
+        match TcpListener::bind(listen_addr) {
+            Ok(listener) => {
+                let pool = TaskPool::new(100);
+                let handler = Arc::new(self.handler);
+                match listener.listen() {
+                    Ok(mut acceptor) => {
+                        for stream in acceptor.incoming() {
+                            match stream {
+                                Ok(stream) => {
+                                    let handler = handler.clone();
+                                    pool.execute(move || {
+                                        TcpReceiver::read_frames(stream, &*handler);
+                                    });
+                                }
+                                Err(..) => continue
+                            }
+                        }
+                    }
+                    Err(..) => panic!()
+                }
+            }
+            Err(..) => panic!()
+        }
+    }
+
+    // Reads a single connection until it closes, splitting the byte stream
+    // on 0x00 and dispatching each NUL-delimited frame as a GELF message.
+    fn read_frames(mut stream: TcpStream, handler: &H) {
+        let mut buf: Vec<u8> = vec![];
+        let mut chunk = [0u8; 4096];
+        loop {
+            match stream.read(&mut chunk) {
+                Ok(n_read) => {
+                    buf.push_all(chunk.slice_to(n_read));
+                    let (frames, rest) = split_frames(buf);
+                    for json in frames.into_iter() {
+                        handler.call(Delivery::new(json));
+                    }
+                    buf = rest;
+                    if buf.len() > MAX_FRAME_LEN {
+                        // No terminating NUL within the size cap; something
+                        // is wrong with this connection (or it isn't really
+                        // speaking GELF/TCP), so stop reading rather than
+                        // let buf grow without bound.
+                        return;
+                    }
+                }
+                Err(..) => return // Connection closed or errored, stop reading.
+            }
+        }
+    }
+}
+
+/// The largest a pending, NUL-less frame is allowed to grow before the
+/// connection is dropped, so a client that never sends `0x00` can't make
+/// `buf` grow without bound.
+const MAX_FRAME_LEN: usize = 1024 * 1024;
+
+/// Splits `buf` on every `0x00`, returning the UTF-8 JSON frames found (in
+/// order, malformed frames dropped) and whatever trailing bytes remain
+/// after the last `0x00` so the caller can prepend them to the next read.
+fn split_frames(buf: Vec<u8>) -> (Vec<String>, Vec<u8>) {
+    let mut frames = vec![];
+    let mut rest = buf;
+    loop {
+        let nul = rest.iter().position(|&b| b == 0x00);
+        match nul {
+            Some(pos) => {
+                match str::from_utf8(&rest[..pos]) {
+                    Ok(json) if ::serde_json::from_str::<::serde_json::Value>(json).is_ok() =>
+                        frames.push(json.to_string()),
+                    _ => {} // Drop the malformed frame.
+                }
+                rest = rest[pos + 1..].to_vec();
+            }
+            None => break
+        }
+    }
+    (frames, rest)
+}
+
 // pub fn udp_receiver_smoke_test<H: Handler>(handler: H) {
 //     let listen_addr = SocketAddr { ip: Ipv4Addr(127, 0, 0, 1), port: 9600 };
 //     println!("{}", listen_addr);
@@ -81,15 +208,203 @@ impl<H: Handler> Receiver<H> {
 //     }
 // }
 
+/// Stops the loop started by `ChannelReceiver::listen`. The loop checks for
+/// the signal before every read (each read times out after 100ms) and then
+/// exits, closing the message channel; `shutdown` returns without waiting
+/// for that.
+pub struct ShutdownHandle {
+    tx: Sender<()>
+}
+
+impl ShutdownHandle {
+    pub fn shutdown(&self) {
+        let _ = self.tx.send(());
+    }
+}
+
+/// A channel-oriented, panic-free alternative to `Receiver`.
+///
+/// Instead of invoking a `Handler` inline, `listen` hands back an
+/// `mpsc::Receiver` that yields every successfully unpacked message as
+/// `Ok(String)` and every per-packet failure as `Err(GelfError)`, so a
+/// socket or unpack error never aborts the process. Partial chunks are fed
+/// into a `ChunkAccumulator` owned by the listen loop; completed
+/// reassemblies flow out the same channel as whole messages. Unlike
+/// `Receiver`, this has an error channel, so `listen_with_keys` can surface
+/// `GelfError::DecryptionFailed` instead of dropping the datagram silently.
+pub struct ChannelReceiver;
+
+impl ChannelReceiver {
+    pub fn listen(listen_addr: SocketAddr) -> (MpscReceiver<Result<String, GelfError>>, ShutdownHandle) {
+        ChannelReceiver::listen_with_keys(listen_addr, vec![])
+    }
+
+    /// Like `listen`, but decrypts every datagram with `ChaCha20Poly1305`
+    /// before unpacking it, trying each of `keys` in turn. A datagram that
+    /// doesn't verify under any key yields `Err(GelfError::DecryptionFailed)`
+    /// on the channel rather than being dropped.
+    pub fn listen_with_keys(listen_addr: SocketAddr, keys: Vec<crypto::Key>) -> (MpscReceiver<Result<String, GelfError>>, ShutdownHandle) {
+        let (tx, rx) = channel();
+        let (shutdown_tx, shutdown_rx) = channel();
+
+        Thread::spawn(move|| {
+            let mut server = match UdpSocket::bind(listen_addr) {
+                Ok(server) => server,
+                Err(e) => {
+                    let _ = tx.send(Err(GelfError::Io(e)));
+                    return;
+                }
+            };
+            // Poll for a shutdown signal between reads rather than blocking
+            // forever on a socket nobody will write to again.
+            server.set_timeout(Some(100));
+            let accumulator = ChunkAccumulator::new();
+
+            loop {
+                if shutdown_rx.try_recv().is_ok() {
+                    break;
+                }
+
+                let mut buf = [0; 1432];
+                match server.recv_from(&mut buf) {
+                    Ok((n_read, _)) => {
+                        let datagram = buf.as_slice().slice_to(n_read);
+                        let decrypted;
+                        let packet = if keys.is_empty() {
+                            datagram
+                        } else {
+                            match crypto::decrypt(datagram, keys.as_slice()) {
+                                Ok(plaintext) => { decrypted = plaintext; decrypted.as_slice() }
+                                Err(e) => {
+                                    if tx.send(Err(e)).is_err() {
+                                        break; // Nobody is listening any more.
+                                    }
+                                    continue;
+                                }
+                            }
+                        };
+                        let delivery = match unpack(packet) {
+                            Ok(Complete(s))    => Some(Ok(s)),
+                            Ok(Partial(chunk)) => match accumulator.accept(chunk) {
+                                Ok(Some(mut set)) => Some(set.unpack()),
+                                Ok(None)          => None,
+                                Err(e)            => Some(Err(e))
+                            },
+                            Err(e) => Some(Err(e))
+                        };
+                        if let Some(result) = delivery {
+                            if tx.send(result).is_err() {
+                                break; // Nobody is listening any more.
+                            }
+                        }
+                    }
+                    // Either the read timed out (so we can recheck the
+                    // shutdown channel) or it's a transient socket error;
+                    // either way, keep the loop alive instead of panicking.
+                    Err(..) => continue
+                }
+            }
+        });
+
+        (rx, ShutdownHandle { tx: shutdown_tx })
+    }
+}
+
+/// A message handed to a `Handler`: the raw, unpacked JSON string alongside
+/// its parsed `Message` when the payload validated as one (invalid or
+/// partially-validating payloads still deliver with `parsed: None`, so a
+/// handler that only cares about the raw string keeps working).
+pub struct Delivery {
+    pub raw: String,
+    pub parsed: Option<Message>
+}
+
+impl Delivery {
+    fn new(raw: String) -> Delivery {
+        let parsed = Message::from_str(raw.as_slice()).ok();
+        Delivery { raw: raw, parsed: parsed }
+    }
+}
+
 pub trait Handler: Send + Sync {
     /// Produce a `Response` from a Request, with the possibility of error.
     ///
     /// If this returns an Err, `catch` is called with the error.
-    fn call(&self, String);
+    fn call(&self, Delivery);
 }
 
 impl<F: Send + Sync + for<'a> Fn(String)> Handler for F {
-    fn call(&self, message: String) {
-        (*self)(message)
+    fn call(&self, delivery: Delivery) {
+        (*self)(delivery.raw)
+    }
+}
+
+#[cfg(test)]
+mod test_split_frames {
+    use super::*;
+
+    #[test]
+    fn splits_one_frame_per_nul() {
+        let buf = b"{\"a\":1}\x00{\"a\":2}\x00".to_vec();
+
+        let (frames, rest) = split_frames(buf);
+
+        assert_eq!(vec!["{\"a\":1}".to_string(), "{\"a\":2}".to_string()], frames);
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn keeps_multiple_frames_arriving_in_a_single_read() {
+        // Three complete frames land in the buffer at once, as if a single
+        // `stream.read` had pulled them all off the wire together.
+        let buf = b"{\"a\":1}\x00{\"a\":2}\x00{\"a\":3}\x00".to_vec();
+
+        let (frames, rest) = split_frames(buf);
+
+        assert_eq!(3, frames.len());
+        assert_eq!("{\"a\":3}", frames[2]);
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn holds_a_partial_frame_for_the_next_read() {
+        // No terminating NUL yet: nothing to deliver, and the bytes must
+        // come back as `rest` so the next read can pick up where this left
+        // off.
+        let buf = b"{\"a\":1}".to_vec();
+
+        let (frames, rest) = split_frames(buf);
+
+        assert!(frames.is_empty());
+        assert_eq!(b"{\"a\":1}".to_vec(), rest);
+    }
+
+    #[test]
+    fn reassembles_a_frame_split_across_reads() {
+        let first_read = b"{\"a\":".to_vec();
+        let (frames, rest) = split_frames(first_read);
+        assert!(frames.is_empty());
+
+        // The rest of the frame, plus its terminating NUL, arrives on the
+        // next read and gets prepended with the held-over bytes exactly
+        // like `read_frames` does.
+        let mut buf = rest;
+        buf.push_all(b"1}\x00");
+        let (frames, rest) = split_frames(buf);
+
+        assert_eq!(vec!["{\"a\":1}".to_string()], frames);
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn drops_a_malformed_frame_but_keeps_parsing_after_it() {
+        let mut buf = vec![0xff, 0xfe]; // Invalid UTF-8.
+        buf.push(0x00);
+        buf.push_all(b"{\"a\":1}\x00");
+
+        let (frames, rest) = split_frames(buf);
+
+        assert_eq!(vec!["{\"a\":1}".to_string()], frames);
+        assert!(rest.is_empty());
     }
 }